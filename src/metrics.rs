@@ -2,13 +2,23 @@ use clap::crate_version;
 use lazy_static::lazy_static;
 use prometheus::{labels, opts, register_int_counter, register_int_gauge, IntCounter, IntGauge};
 
-use crate::peer_db::get_peer_db_type;
+use crate::peer_db::{get_peer_db_type, PeerDatabase};
 
 lazy_static! {
   pub static ref PEER_GAUGE: IntGauge =
     register_int_gauge!("zn_tracker_peers", "Peers in database").unwrap();
   pub static ref HASH_GAUGE: IntGauge =
     register_int_gauge!("zn_tracker_hashes", "Hashes in database").unwrap();
+  pub static ref DB_SIZE_GAUGE: IntGauge = register_int_gauge!(
+    "zn_tracker_db_size_bytes",
+    "Approximate on-disk (or in-memory) size of the peer database"
+  )
+  .unwrap();
+  pub static ref PEER_HASHES_GAUGE: IntGauge = register_int_gauge!(
+    "zn_tracker_peer_hashes_total",
+    "Rows in the peer_hashes link table"
+  )
+  .unwrap();
   pub static ref REQUEST_COUNTER: IntCounter =
     register_int_counter!("zn_tracker_requests_total", "Requests received").unwrap();
   pub static ref OPENED_CONNECTIONS: IntCounter = register_int_counter!(
@@ -33,3 +43,17 @@ lazy_static! {
   ))
   .unwrap();
 }
+
+/// Refreshes `PEER_GAUGE`, `HASH_GAUGE`, `DB_SIZE_GAUGE` and `PEER_HASHES_GAUGE` from `db`'s
+/// current counts and storage cost. Call this on the same tick that drives
+/// `cleanup_peers`/`cleanup_hashes`, so the gauges stay in lockstep with the database.
+pub fn refresh_peer_db_metrics<DB: PeerDatabase>(db: &DB) -> Result<(), DB::Error> {
+  PEER_GAUGE.set(db.get_peer_count()? as i64);
+  HASH_GAUGE.set(db.get_hash_count()? as i64);
+
+  let stats = db.storage_stats()?;
+  DB_SIZE_GAUGE.set(stats.size_bytes as i64);
+  PEER_HASHES_GAUGE.set(stats.peer_hashes_total as i64);
+
+  Ok(())
+}
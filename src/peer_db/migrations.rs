@@ -0,0 +1,63 @@
+use sqlite::Connection;
+
+use super::sqlite::Error;
+
+/// Each entry is applied once, in order, the first time a database is opened at a
+/// `user_version` below its 1-based step number. Steps must never be edited after release; add a new
+/// one instead so existing databases keep migrating forward instead of re-running history.
+const MIGRATIONS: &[&str] = &[
+  // 1: initial schema
+  "
+  CREATE TABLE peers (
+    pk INTEGER PRIMARY KEY AUTOINCREMENT,
+    address TEXT UNIQUE NOT NULL,
+    date_added TIMESTAMP,
+    last_seen TIMESTAMP
+  );
+  CREATE TABLE hashes (
+    pk INTEGER PRIMARY KEY AUTOINCREMENT,
+    hash BLOB UNIQUE NOT NULL
+  );
+  CREATE TABLE peer_hashes (
+    peer_pk INTEGER REFERENCES peers(pk),
+    hash_pk INTEGER REFERENCES hashes(pk),
+    UNIQUE(peer_pk, hash_pk)
+  );
+  ",
+  // 2: track per-peer connection failures so unreliable peers can be evicted
+  "
+  ALTER TABLE peers ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+  ",
+  // 3: record advertised capability bitmasks, self-reported and gossiped separately
+  "
+  ALTER TABLE peers ADD COLUMN reported_capabilities INTEGER NOT NULL DEFAULT 0;
+  ALTER TABLE peers ADD COLUMN gossiped_capabilities INTEGER NOT NULL DEFAULT 0;
+  ",
+];
+
+/// Brings `conn` up to the latest schema version, reading and advancing `PRAGMA user_version`.
+pub fn run(conn: &Connection) -> Result<(), Error> {
+  let current_version = user_version(conn)?;
+
+  for (index, migration) in MIGRATIONS.iter().enumerate() {
+    let version = index + 1;
+    if version <= current_version {
+      continue;
+    }
+
+    conn.execute("BEGIN;")?;
+    conn.execute(*migration)?;
+    conn.execute(format!("PRAGMA user_version = {};", version))?;
+    conn.execute("COMMIT;")?;
+  }
+
+  Ok(())
+}
+
+fn user_version(conn: &Connection) -> Result<usize, Error> {
+  let mut cursor = conn.prepare("PRAGMA user_version;")?.into_cursor();
+  match cursor.next()? {
+    Some(row) => Ok(row[0].as_integer().unwrap() as usize),
+    None => Ok(0),
+  }
+}
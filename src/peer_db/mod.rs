@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use zeronet_protocol::PeerAddr;
+
+mod migrations;
+mod pool;
+pub mod sqlite;
+
+static FILE_BACKED: AtomicBool = AtomicBool::new(false);
+static ENCRYPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+  pub address:    PeerAddr,
+  pub date_added: SystemTime,
+  pub last_seen:  SystemTime,
+  /// Capability/service bitmask the peer announced about itself.
+  pub reported_capabilities: u32,
+  /// Capability/service bitmask learned about the peer secondhand, via another peer.
+  pub gossiped_capabilities: u32,
+}
+
+impl Peer {
+  /// The capability set to treat as authoritative: `reported_capabilities` when the peer
+  /// has announced itself, otherwise whatever was gossiped about it.
+  pub fn effective_capabilities(&self) -> u32 {
+    if self.reported_capabilities != 0 {
+      self.reported_capabilities
+    } else {
+      self.gossiped_capabilities
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hash(pub Vec<u8>);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StorageStats {
+  /// Approximate size of the database, in bytes (`page_count * page_size`).
+  pub size_bytes:        u64,
+  /// Number of rows in the `peer_hashes` link table.
+  pub peer_hashes_total: usize,
+}
+
+pub trait PeerDatabase {
+  type Error;
+
+  fn update_peer(&mut self, peer: Peer, hashes: Vec<Hash>) -> Result<bool, Self::Error>;
+  fn remove_peer(&mut self, peer_address: &PeerAddr) -> Result<Option<Peer>, Self::Error>;
+  fn get_peer(&self, peer_address: &PeerAddr) -> Result<Option<Peer>, Self::Error>;
+  fn get_peers(&self) -> Result<Vec<Peer>, Self::Error>;
+  fn get_peers_for_hash(&self, hash: &Hash) -> Result<Vec<Peer>, Self::Error>;
+  /// Like `get_peers_for_hash`, but only returns peers whose effective capabilities
+  /// (see `Peer::effective_capabilities`) include every flag set in `required_caps`.
+  fn get_peers_for_hash_with_caps(&self, hash: &Hash, required_caps: u32) -> Result<Vec<Peer>, Self::Error>;
+  fn get_hashes(&self) -> Result<Vec<(Hash, usize)>, Self::Error>;
+  fn get_peer_count(&self) -> Result<usize, Self::Error>;
+  fn get_hash_count(&self) -> Result<usize, Self::Error>;
+  fn cleanup_peers(&mut self, timestamp: SystemTime) -> Result<usize, Self::Error>;
+  fn cleanup_hashes(&mut self) -> Result<usize, Self::Error>;
+
+  /// Records a failed connection attempt to `addr` and returns the peer's new failure count,
+  /// or `None` if `addr` is not a known peer.
+  fn record_failure(&mut self, addr: &PeerAddr) -> Result<Option<usize>, Self::Error>;
+  /// Resets a peer's failure count back to 0, e.g. after a successful connection.
+  fn record_success(&mut self, addr: &PeerAddr) -> Result<(), Self::Error>;
+
+  /// Reports the storage cost of the database, for operators tuning cleanup schedules.
+  fn storage_stats(&self) -> Result<StorageStats, Self::Error>;
+}
+
+/// Reports the kind of backend currently in use, for the `peerdb_type` build-info label.
+pub fn get_peer_db_type() -> &'static str {
+  match (FILE_BACKED.load(Ordering::Relaxed), ENCRYPTED.load(Ordering::Relaxed)) {
+    (true, true) => "sqlite-file-encrypted",
+    (true, false) => "sqlite-file",
+    (false, true) => "sqlite-memory-encrypted",
+    (false, false) => "sqlite-memory",
+  }
+}
+
+pub(crate) fn set_file_backed(file_backed: bool) {
+  FILE_BACKED.store(file_backed, Ordering::Relaxed);
+}
+
+pub(crate) fn set_encrypted(encrypted: bool) {
+  ENCRYPTED.store(encrypted, Ordering::Relaxed);
+}
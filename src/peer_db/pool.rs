@@ -0,0 +1,195 @@
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use sqlite::Connection;
+
+use super::sqlite::Error;
+
+/// How many pooled read connections to keep open against a file-backed database.
+const READ_POOL_SIZE: usize = 4;
+
+enum Target {
+  Memory,
+  File(PathBuf),
+}
+
+/// A pool of connections used only for reads plus one dedicated writer, so readers never block
+/// behind whichever request currently holds the write lock.
+///
+/// A `:memory:` database only exists inside the connection that created it, so separate
+/// reader connections would each see their own empty schema. For `Target::Memory`, reads
+/// are routed straight to the write connection instead of a separate pool.
+pub struct Pool {
+  target:     Target,
+  write_conn: Mutex<Connection>,
+  read_conns: Mutex<Vec<Connection>>,
+  available:  Condvar,
+}
+
+impl Pool {
+  pub fn open(path: Option<&std::path::Path>) -> Result<Pool, Error> {
+    Pool::open_with_key(path, None)
+  }
+
+  /// Like `open`, but runs `PRAGMA key` on every connection before anything else touches
+  /// it, enabling SQLCipher-style page encryption for the whole database file.
+  pub fn open_with_key(path: Option<&std::path::Path>, key: Option<&str>) -> Result<Pool, Error> {
+    let target = match path {
+      Some(path) => Target::File(path.to_path_buf()),
+      None => Target::Memory,
+    };
+    let write_conn = open_one(&target, key)?;
+    enable_wal(&write_conn)?;
+
+    let read_conns = match target {
+      Target::Memory => Vec::new(),
+      Target::File(_) => {
+        let mut conns = Vec::with_capacity(READ_POOL_SIZE);
+        for _ in 0..READ_POOL_SIZE {
+          let conn = open_one(&target, key)?;
+          enable_wal(&conn)?;
+          conns.push(conn);
+        }
+        conns
+      }
+    };
+
+    Ok(Pool {
+      target,
+      write_conn: Mutex::new(write_conn),
+      read_conns: Mutex::new(read_conns),
+      available: Condvar::new(),
+    })
+  }
+
+  fn is_memory(&self) -> bool {
+    matches!(self.target, Target::Memory)
+  }
+
+  /// Rekeys the database in place. The write connection is rekeyed with `PRAGMA rekey`;
+  /// pooled read connections are closed and reopened with the new key rather than handed
+  /// `PRAGMA key` post-open, since that pragma only establishes the key an already-open
+  /// connection uses to decrypt pages and is a no-op against a connection that opened
+  /// successfully under the old one.
+  pub fn set_passphrase(&self, new_key: &str) -> Result<(), Error> {
+    self
+      .write_conn
+      .lock()
+      .unwrap()
+      .execute(pragma("rekey", new_key))?;
+
+    if self.is_memory() {
+      return Ok(());
+    }
+
+    let mut conns = self.read_conns.lock().unwrap();
+    let pool_size = conns.len();
+    conns.clear();
+    for _ in 0..pool_size {
+      let conn = open_one(&self.target, Some(new_key))?;
+      enable_wal(&conn)?;
+      conns.push(conn);
+    }
+    Ok(())
+  }
+
+  /// Runs `f` with exclusive access to the write connection.
+  pub fn with_write<T>(&self, f: impl FnOnce(&Connection) -> Result<T, Error>) -> Result<T, Error> {
+    let conn = self.write_conn.lock().unwrap();
+    f(&conn)
+  }
+
+  /// Checks out a read connection. For a file-backed database this blocks until a pooled
+  /// connection is free; for an in-memory database it hands back the write connection,
+  /// since that's the only connection that actually sees the data.
+  pub fn checkout_read(&self) -> PooledConnection {
+    if self.is_memory() {
+      return PooledConnection::Write(self.write_conn.lock().unwrap());
+    }
+
+    let mut conns = self.read_conns.lock().unwrap();
+    loop {
+      if let Some(conn) = conns.pop() {
+        return PooledConnection::Pooled {
+          conn:      Some(conn),
+          pool:      &self.read_conns,
+          available: &self.available,
+        };
+      }
+      conns = self.available.wait(conns).unwrap();
+    }
+  }
+}
+
+pub enum PooledConnection<'a> {
+  Pooled {
+    conn:      Option<Connection>,
+    pool:      &'a Mutex<Vec<Connection>>,
+    available: &'a Condvar,
+  },
+  Write(MutexGuard<'a, Connection>),
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+  type Target = Connection;
+
+  fn deref(&self) -> &Connection {
+    match self {
+      PooledConnection::Pooled { conn, .. } => conn.as_ref().unwrap(),
+      PooledConnection::Write(guard) => guard,
+    }
+  }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+  fn drop(&mut self) {
+    if let PooledConnection::Pooled { conn, pool, available } = self {
+      if let Some(conn) = conn.take() {
+        pool.lock().unwrap().push(conn);
+        available.notify_one();
+      }
+    }
+  }
+}
+
+fn open_one(target: &Target, key: Option<&str>) -> Result<Connection, Error> {
+  let conn = match target {
+    Target::File(path) => sqlite::open(path)?,
+    Target::Memory => sqlite::open(":memory:")?,
+  };
+  if let Some(key) = key {
+    conn.execute(pragma("key", key))?;
+    assert_encrypted(&conn)?;
+  }
+  Ok(conn)
+}
+
+/// Confirms `PRAGMA key` actually enabled page encryption. On a stock libsqlite3 build,
+/// `PRAGMA key` is an unrecognized pragma that SQLite silently ignores, which would
+/// otherwise leave `open_encrypted` quietly returning a plaintext database while
+/// `get_peer_db_type()` claims it's encrypted.
+fn assert_encrypted(conn: &Connection) -> Result<(), Error> {
+  let mut cursor = conn.prepare("PRAGMA cipher_version;")?.into_cursor();
+  let encrypted = match cursor.next()? {
+    Some(row) => !row[0].as_string().unwrap_or("").is_empty(),
+    None => false,
+  };
+
+  if encrypted {
+    Ok(())
+  } else {
+    Err(Error::EncryptionUnavailable)
+  }
+}
+
+fn enable_wal(conn: &Connection) -> Result<(), Error> {
+  conn.execute("PRAGMA journal_mode = WAL;")?;
+  Ok(())
+}
+
+/// Builds a `PRAGMA key = '...'`/`PRAGMA rekey = '...'` statement. SQLite pragmas don't
+/// accept bound parameters, so the value is quoted by doubling embedded single quotes.
+fn pragma(name: &str, value: &str) -> String {
+  format!("PRAGMA {} = '{}';", name, value.replace('\'', "''"))
+}
@@ -1,10 +1,13 @@
+use std::path::Path;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use sqlite::{Connection, Value};
+use sqlite::Value;
 use thiserror::Error;
 use zeronet_protocol::PeerAddr;
 
-use super::{Hash, Peer, PeerDatabase};
+use super::migrations;
+use super::pool::Pool;
+use super::{Hash, Peer, PeerDatabase, StorageStats};
 
 fn unix_to_timestamp(seconds: i64) -> SystemTime {
   UNIX_EPOCH
@@ -16,115 +19,160 @@ fn timestamp_to_unix(timestamp: SystemTime) -> i64 {
   timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
 }
 
+/// Builds a `Peer` from a row of a `SELECT address, date_added, last_seen,
+/// reported_capabilities, gossiped_capabilities` query.
+fn peer_from_row(row: &[Value]) -> Peer {
+  Peer {
+    address:                PeerAddr::parse(row[0].as_string().unwrap()).unwrap(),
+    date_added:             unix_to_timestamp(row[1].as_integer().unwrap()),
+    last_seen:              unix_to_timestamp(row[2].as_integer().unwrap()),
+    reported_capabilities:  row[3].as_integer().unwrap() as u32,
+    gossiped_capabilities:  row[4].as_integer().unwrap() as u32,
+  }
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
   #[error("error with sqlite")]
   SQLite(#[from] sqlite::Error),
+  #[error("SQLite build does not support encryption; refusing to open as encrypted")]
+  EncryptionUnavailable,
 }
 
+/// Peers with at least this many consecutive failures are treated as unreliable: they're
+/// dropped by `cleanup_peers` regardless of `last_seen`, and sorted to the back of announce
+/// responses by `get_peers_for_hash`.
+pub const MAX_PEER_FAILURES: i64 = 8;
+
 pub struct PeerDB {
-  conn: Connection,
+  pool: Pool,
 }
 
 impl PeerDB {
+  /// Opens an in-memory database. State is lost on restart; prefer `open` for a tracker
+  /// that should remember its peers across process restarts.
   pub fn new() -> Result<PeerDB, Error> {
-    // TODO: option to load database from a path
-    let connection = sqlite::open(":memory:").unwrap();
-    connection
-      .execute(
-        "
-      CREATE TABLE peers (
-        pk INTEGER PRIMARY KEY AUTOINCREMENT,
-        address TEXT UNIQUE NOT NULL,
-        date_added TIMESTAMP,
-        last_seen TIMESTAMP
-      );
-      CREATE TABLE hashes (
-        pk INTEGER PRIMARY KEY AUTOINCREMENT,
-        hash BLOB UNIQUE NOT NULL
-      );
-      CREATE TABLE peer_hashes (
-        peer_pk INTEGER REFERENCES peers(pk),
-        hash_pk INTEGER REFERENCES hashes(pk),
-        UNIQUE(peer_pk, hash_pk)
-      );
-    ",
-      )
-      .unwrap();
-    let db = PeerDB { conn: connection };
-    return Ok(db);
+    PeerDB::open(None)
+  }
+
+  /// Opens the database at `path`, or falls back to an in-memory database when `path` is
+  /// `None`. Applies any pending schema migrations before returning.
+  pub fn open(path: Option<&Path>) -> Result<PeerDB, Error> {
+    let pool = Pool::open(path)?;
+    pool.with_write(|conn| migrations::run(conn))?;
+    super::set_file_backed(path.is_some());
+    super::set_encrypted(false);
+
+    Ok(PeerDB { pool })
+  }
+
+  /// Opens the database at `path` with SQLCipher-style page encryption, so peer addresses
+  /// and site hashes stay unreadable at rest on shared infrastructure.
+  pub fn open_encrypted(path: &Path, key: &str) -> Result<PeerDB, Error> {
+    let pool = Pool::open_with_key(Some(path), Some(key))?;
+    pool.with_write(|conn| migrations::run(conn))?;
+    super::set_file_backed(true);
+    super::set_encrypted(true);
+
+    Ok(PeerDB { pool })
+  }
+
+  /// Rekeys the database and carries the new passphrase over to every pooled connection.
+  pub fn set_passphrase(&mut self, new_key: &str) -> Result<(), Error> {
+    self.pool.set_passphrase(new_key)?;
+    super::set_encrypted(true);
+
+    Ok(())
   }
 
   pub fn upsert_peer(&mut self, peer: &Peer) -> Result<bool, Error> {
-    let mut statement = self
-      .conn
-      .prepare(
-        "
-      INSERT INTO peers
-        (address, date_added, last_seen)
-      VALUES
-        (:address, :date_added, :last_seen)
-      ON CONFLICT (address) DO UPDATE SET
-        last_seen = :last_seen
-      RETURNING last_seen;
-      ",
-      )
-      .unwrap();
+    let date_added = self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        INSERT INTO peers
+          (address, date_added, last_seen, reported_capabilities, gossiped_capabilities)
+        VALUES
+          (:address, :date_added, :last_seen, :reported_capabilities, :gossiped_capabilities)
+        ON CONFLICT (address) DO UPDATE SET
+          last_seen = :last_seen,
+          reported_capabilities = CASE WHEN :reported_capabilities != 0
+            THEN :reported_capabilities ELSE peers.reported_capabilities END,
+          gossiped_capabilities = CASE WHEN :gossiped_capabilities != 0
+            THEN :gossiped_capabilities ELSE peers.gossiped_capabilities END
+        RETURNING last_seen;
+        ",
+        )
+        .unwrap();
+      statement
+        .bind_by_name(":address", peer.address.to_string().as_str())
+        .unwrap();
+      statement
+        .bind_by_name(":date_added", timestamp_to_unix(peer.date_added))
+        .unwrap();
+      statement
+        .bind_by_name(":last_seen", timestamp_to_unix(peer.last_seen))
+        .unwrap();
+      statement
+        .bind_by_name(":reported_capabilities", peer.reported_capabilities as i64)
+        .unwrap();
+      statement
+        .bind_by_name(":gossiped_capabilities", peer.gossiped_capabilities as i64)
+        .unwrap();
+      statement.next().unwrap();
+
+      Ok(statement.read::<i64>(0).unwrap())
+    })?;
     let date_updated = timestamp_to_unix(peer.date_added);
-    statement
-      .bind_by_name(":address", peer.address.to_string().as_str())
-      .unwrap();
-    statement.bind_by_name(":date_added", date_updated).unwrap();
-    statement
-      .bind_by_name(":last_seen", timestamp_to_unix(peer.last_seen))
-      .unwrap();
-    statement.next().unwrap();
-    let date_added = statement.read::<i64>(0).unwrap();
+
+    self.record_success(&peer.address)?;
 
     return Ok(date_updated != date_added);
   }
 
   pub fn insert_hash(&mut self, hash: &Hash) -> Result<(), Error> {
-    let mut statement = self
-      .conn
-      .prepare(
-        "
-      INSERT INTO hashes
-        (hash)
-      VALUES
-        (:hash)
-      ON CONFLICT (hash) DO NOTHING;
-    ",
-      )
-      .unwrap();
-    statement.bind_by_name(":hash", hash.0.as_slice()).unwrap();
-    statement.next().unwrap();
+    self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        INSERT INTO hashes
+          (hash)
+        VALUES
+          (:hash)
+        ON CONFLICT (hash) DO NOTHING;
+      ",
+        )
+        .unwrap();
+      statement.bind_by_name(":hash", hash.0.as_slice()).unwrap();
+      statement.next().unwrap();
 
-    Ok(())
+      Ok(())
+    })
   }
 
   pub fn link(&mut self, hash: &Hash, peer_address: &PeerAddr) -> Result<(), Error> {
-    let mut statement = self
-      .conn
-      .prepare(
-        "
-      INSERT INTO peer_hashes
-        (peer_pk, hash_pk)
-      VALUES (
-        (SELECT pk FROM peers WHERE address = ?),
-        (SELECT pk FROM hashes WHERE hash = ?)
-      )
-      ON CONFLICT (peer_pk, hash_pk) DO NOTHING;
-    ",
-      )
-      .unwrap();
-    statement
-      .bind(1, peer_address.to_string().as_str())
-      .unwrap();
-    statement.bind(2, hash.0.as_slice()).unwrap();
-    statement.next().unwrap();
+    self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        INSERT INTO peer_hashes
+          (peer_pk, hash_pk)
+        VALUES (
+          (SELECT pk FROM peers WHERE address = ?),
+          (SELECT pk FROM hashes WHERE hash = ?)
+        )
+        ON CONFLICT (peer_pk, hash_pk) DO NOTHING;
+      ",
+        )
+        .unwrap();
+      statement
+        .bind(1, peer_address.to_string().as_str())
+        .unwrap();
+      statement.bind(2, hash.0.as_slice()).unwrap();
+      statement.next().unwrap();
 
-    Ok(())
+      Ok(())
+    })
   }
 }
 
@@ -142,52 +190,47 @@ impl PeerDatabase for PeerDB {
   }
 
   fn remove_peer(&mut self, peer_address: &PeerAddr) -> Result<Option<Peer>, Self::Error> {
-    let mut statement = self
-      .conn
-      .prepare(
-        "
-      DELETE FROM peer_hashes
-      WHERE peer_pk IN (
-        SELECT pk FROM peers WHERE address = ?
-      );
-    ",
-      )
-      .unwrap();
-    statement
-      .bind(1, peer_address.to_string().as_str())
-      .unwrap();
-    let mut cursor = self
-      .conn
-      .prepare(
-        "
-      DELETE FROM peers
-      WHERE address = ?
-      RETURNING address, date_added, last_seen;
-    ",
-      )
-      .unwrap()
-      .into_cursor();
-    cursor
-      .bind(&[Value::String(peer_address.to_string())])
-      .unwrap();
-    if let Some(row) = cursor.next().unwrap() {
-      let peer = Peer {
-        address:    PeerAddr::parse(row[0].as_string().unwrap()).unwrap(),
-        date_added: unix_to_timestamp(row[1].as_integer().unwrap()),
-        last_seen:  unix_to_timestamp(row[2].as_integer().unwrap()),
-      };
-      return Ok(Some(peer));
-    } else {
-      return Ok(None);
-    }
+    self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        DELETE FROM peer_hashes
+        WHERE peer_pk IN (
+          SELECT pk FROM peers WHERE address = ?
+        );
+      ",
+        )
+        .unwrap();
+      statement
+        .bind(1, peer_address.to_string().as_str())
+        .unwrap();
+      let mut cursor = conn
+        .prepare(
+          "
+        DELETE FROM peers
+        WHERE address = ?
+        RETURNING address, date_added, last_seen, reported_capabilities, gossiped_capabilities;
+      ",
+        )
+        .unwrap()
+        .into_cursor();
+      cursor
+        .bind(&[Value::String(peer_address.to_string())])
+        .unwrap();
+      if let Some(row) = cursor.next().unwrap() {
+        Ok(Some(peer_from_row(row)))
+      } else {
+        Ok(None)
+      }
+    })
   }
 
   fn get_peer(&self, peer_address: &PeerAddr) -> Result<Option<Peer>, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare(
         "
-      SELECT address, date_added, last_seen
+      SELECT address, date_added, last_seen, reported_capabilities, gossiped_capabilities
       FROM peers
       WHERE address = ?;
     ",
@@ -198,23 +241,18 @@ impl PeerDatabase for PeerDB {
       .bind(&[Value::String(peer_address.to_string())])
       .unwrap();
     if let Some(row) = cursor.next().unwrap() {
-      let peer = Peer {
-        address:    PeerAddr::parse(row[0].as_string().unwrap()).unwrap(),
-        date_added: unix_to_timestamp(row[1].as_integer().unwrap()),
-        last_seen:  unix_to_timestamp(row[2].as_integer().unwrap()),
-      };
-      return Ok(Some(peer));
+      return Ok(Some(peer_from_row(row)));
     } else {
       return Ok(None);
     }
   }
 
   fn get_peers(&self) -> Result<Vec<Peer>, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare(
         "
-      SELECT address, date_added, last_seen
+      SELECT address, date_added, last_seen, reported_capabilities, gossiped_capabilities
       FROM peers;
     ",
       )
@@ -222,25 +260,22 @@ impl PeerDatabase for PeerDB {
       .into_cursor();
     let mut peers = Vec::new();
     while let Some(row) = cursor.next().unwrap() {
-      peers.push(Peer {
-        address:    PeerAddr::parse(row[0].as_string().unwrap()).unwrap(),
-        date_added: unix_to_timestamp(row[1].as_integer().unwrap()),
-        last_seen:  unix_to_timestamp(row[2].as_integer().unwrap()),
-      })
+      peers.push(peer_from_row(row))
     }
     return Ok(peers);
   }
 
   fn get_peers_for_hash(&self, hash: &Hash) -> Result<Vec<Peer>, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare(
         "
-      SELECT address, date_added, last_seen
+      SELECT address, date_added, last_seen, reported_capabilities, gossiped_capabilities
       FROM hashes h
         INNER JOIN peer_hashes ph ON (h.pk = ph.hash_pk)
         LEFT JOIN peers p ON (p.pk = ph.peer_pk)
-      WHERE hash = ?;
+      WHERE hash = ?
+      ORDER BY p.failures ASC;
     ",
       )
       .unwrap()
@@ -248,18 +283,47 @@ impl PeerDatabase for PeerDB {
     cursor.bind(&[Value::Binary(hash.0.clone())]).unwrap();
     let mut peers = Vec::new();
     while let Some(row) = cursor.next().unwrap() {
-      peers.push(Peer {
-        address:    PeerAddr::parse(row[0].as_string().unwrap()).unwrap(),
-        date_added: unix_to_timestamp(row[1].as_integer().unwrap()),
-        last_seen:  unix_to_timestamp(row[2].as_integer().unwrap()),
-      })
+      peers.push(peer_from_row(row))
+    }
+    return Ok(peers);
+  }
+
+  fn get_peers_for_hash_with_caps(&self, hash: &Hash, required_caps: u32) -> Result<Vec<Peer>, Self::Error> {
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
+      .prepare(
+        "
+      SELECT address, date_added, last_seen, reported_capabilities, gossiped_capabilities
+      FROM hashes h
+        INNER JOIN peer_hashes ph ON (h.pk = ph.hash_pk)
+        LEFT JOIN peers p ON (p.pk = ph.peer_pk)
+      WHERE hash = ?
+        AND (
+          (CASE WHEN p.reported_capabilities != 0 THEN p.reported_capabilities ELSE p.gossiped_capabilities END)
+          & ?
+        ) = ?
+      ORDER BY p.failures ASC;
+    ",
+      )
+      .unwrap()
+      .into_cursor();
+    cursor
+      .bind(&[
+        Value::Binary(hash.0.clone()),
+        Value::Integer(required_caps as i64),
+        Value::Integer(required_caps as i64),
+      ])
+      .unwrap();
+    let mut peers = Vec::new();
+    while let Some(row) = cursor.next().unwrap() {
+      peers.push(peer_from_row(row))
     }
     return Ok(peers);
   }
 
   fn get_hashes(&self) -> Result<Vec<(Hash, usize)>, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare(
         "
       SELECT hash, COUNT(peer_pk)
@@ -282,8 +346,8 @@ impl PeerDatabase for PeerDB {
   }
 
   fn get_peer_count(&self) -> Result<usize, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare("SELECT COUNT(pk) FROM peers;")
       .unwrap()
       .into_cursor();
@@ -295,8 +359,8 @@ impl PeerDatabase for PeerDB {
   }
 
   fn get_hash_count(&self) -> Result<usize, Self::Error> {
-    let mut cursor = self
-      .conn
+    let conn = self.pool.checkout_read();
+    let mut cursor = conn
       .prepare("SELECT COUNT(pk) FROM hashes;")
       .unwrap()
       .into_cursor();
@@ -308,39 +372,136 @@ impl PeerDatabase for PeerDB {
   }
 
   fn cleanup_peers(&mut self, timestamp: SystemTime) -> Result<usize, Self::Error> {
-    let mut statement = self
-      .conn
-      .prepare(
-        "
-      DELETE FROM peer_hashes WHERE peer_pk IN (SELECT pk FROM peers WHERE last_seen < :timestamp);
-      DELETE FROM peers WHERE last_seen < :timestamp;
-    ",
-      )
-      .unwrap();
-    statement
-      .bind_by_name(":timestamp", timestamp_to_unix(timestamp))
-      .unwrap();
-    statement.next().unwrap();
+    self.pool.with_write(|conn| {
+      // `prepare` only compiles the first statement in a string, so the two DELETEs must be
+      // run as separate prepared statements rather than one multi-statement string.
+      let mut unlink = conn
+        .prepare(
+          "
+        DELETE FROM peer_hashes WHERE peer_pk IN (
+          SELECT pk FROM peers WHERE last_seen < :timestamp OR failures >= :max_failures
+        );
+      ",
+        )
+        .unwrap();
+      unlink
+        .bind_by_name(":timestamp", timestamp_to_unix(timestamp))
+        .unwrap();
+      unlink
+        .bind_by_name(":max_failures", MAX_PEER_FAILURES)
+        .unwrap();
+      unlink.next().unwrap();
+
+      let mut evict = conn
+        .prepare(
+          "
+        DELETE FROM peers WHERE last_seen < :timestamp OR failures >= :max_failures;
+      ",
+        )
+        .unwrap();
+      evict
+        .bind_by_name(":timestamp", timestamp_to_unix(timestamp))
+        .unwrap();
+      evict
+        .bind_by_name(":max_failures", MAX_PEER_FAILURES)
+        .unwrap();
+      evict.next().unwrap();
 
-    Ok(self.conn.change_count())
+      Ok(conn.change_count())
+    })
   }
 
   fn cleanup_hashes(&mut self) -> Result<usize, Self::Error> {
-    self
-      .conn
-      .execute(
-        "
-      DELETE FROM hashes
-      WHERE pk IN (
-        SELECT pk FROM (
-          SELECT hash_pk pk, COUNT(peer_pk) count FROM peer_hashes
+    self.pool.with_write(|conn| {
+      conn
+        .execute(
+          "
+        DELETE FROM hashes
+        WHERE pk IN (
+          SELECT pk FROM (
+            SELECT hash_pk pk, COUNT(peer_pk) count FROM peer_hashes
+          )
+          WHERE count = 0
+        );
+      ",
         )
-        WHERE count = 0
-      );
-    ",
-      )
-      .unwrap();
+        .unwrap();
+
+      Ok(conn.change_count())
+    })
+  }
+
+  fn record_failure(&mut self, addr: &PeerAddr) -> Result<Option<usize>, Self::Error> {
+    self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        UPDATE peers
+        SET failures = failures + 1
+        WHERE address = :address
+        RETURNING failures;
+      ",
+        )
+        .unwrap();
+      statement
+        .bind_by_name(":address", addr.to_string().as_str())
+        .unwrap();
+
+      // No row means `addr` isn't a known peer; that's distinct from a real peer whose
+      // failure count happens to read back as 0, so don't collapse the two into `Ok(0)`.
+      match statement.next().unwrap() {
+        sqlite::State::Row => Ok(Some(statement.read::<i64>(0).unwrap() as usize)),
+        sqlite::State::Done => Ok(None),
+      }
+    })
+  }
+
+  fn record_success(&mut self, addr: &PeerAddr) -> Result<(), Self::Error> {
+    self.pool.with_write(|conn| {
+      let mut statement = conn
+        .prepare(
+          "
+        UPDATE peers
+        SET failures = 0
+        WHERE address = :address;
+      ",
+        )
+        .unwrap();
+      statement
+        .bind_by_name(":address", addr.to_string().as_str())
+        .unwrap();
+      statement.next().unwrap();
+
+      Ok(())
+    })
+  }
+
+  fn storage_stats(&self) -> Result<StorageStats, Self::Error> {
+    let conn = self.pool.checkout_read();
+    let page_count = pragma_integer(&conn, "page_count")?;
+    let page_size = pragma_integer(&conn, "page_size")?;
+
+    let mut cursor = conn
+      .prepare("SELECT COUNT(*) FROM peer_hashes;")
+      .unwrap()
+      .into_cursor();
+    let peer_hashes_total = match cursor.next().unwrap() {
+      Some(row) => row[0].as_integer().unwrap() as usize,
+      None => 0,
+    };
+
+    Ok(StorageStats {
+      size_bytes: (page_count * page_size) as u64,
+      peer_hashes_total,
+    })
+  }
+}
 
-    Ok(self.conn.change_count())
+/// Reads a numeric `PRAGMA`, e.g. `page_count`/`page_size`, used to estimate storage cost.
+fn pragma_integer(conn: &sqlite::Connection, name: &str) -> Result<i64, Error> {
+  let mut cursor = conn.prepare(format!("PRAGMA {};", name))?.into_cursor();
+  match cursor.next()? {
+    Some(row) => Ok(row[0].as_integer().unwrap()),
+    None => Ok(0),
   }
 }
\ No newline at end of file